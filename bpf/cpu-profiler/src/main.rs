@@ -12,7 +12,7 @@
 //
 // use vmlinux::task_struct;
 
-use aya_bpf::{bindings::{BPF_F_USER_STACK, BPF_NOEXIST}, macros::{map, perf_event}, maps::{Array, HashMap, StackTrace}, programs::PerfEventContext, BpfContext, PtRegs};
+use aya_bpf::{bindings::{BPF_F_USER_STACK, BPF_F_STACK_BUILD_ID, BPF_NOEXIST}, helpers::bpf_probe_read, macros::{map, perf_event}, maps::{Array, HashMap, PerCpuArray, StackTrace}, programs::PerfEventContext, BpfContext, PtRegs};
 
 #[no_mangle]
 #[link_section = "license"]
@@ -20,14 +20,23 @@ static LICENSE: [u8; 4] = *b"GPL\0";
 
 const MAX_STACK_ADDRESSES: u32 = 1024;
 const MAX_STACK_DEPTH: u32 = 127;
-// Absolute maximum stack size limit is 512 bytes for eBPF programs.
-// 512 / 8 byte address = 64.
-// Since some stack slots for other variables this needs to be lower.
-// TODO(kakkoyun): Use BPF_MAP_TYPE_PER_CPU_ARRAY to circumvent this limit.
-// - https://lwn.net/Articles/674443/
-const EH_FRAME_MAX_STACK_DEPTH: usize = 48;
 const MAX_BIN_SEARCH_DEPTH: u32 = 24;
 const EH_FRAME_ENTRIES: u32 = 0xff_ffff;
+const MAX_UNWIND_TABLES: u32 = 1024;
+const EH_FRAME_STACK_TRACE_ENTRIES: u32 = 1024;
+const MAX_STACK_PROBE_DEPTH: u32 = 8;
+const BUILD_ID_SIZE: usize = 20;
+
+// Layout of `build_id_stack_traces` entries, matching `struct bpf_stack_build_id`.
+// Not read from the BPF side, so allow the dead_code lint.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct BuildIdFrame {
+    status: u32,
+    build_id: [u8; BUILD_ID_SIZE],
+    offset: u64,
+}
 
 #[derive(Clone, Copy)]
 #[repr(C)]
@@ -50,20 +59,39 @@ static mut COUNTS: HashMap<StackCountKey, u64> =
 #[map(name = "stack_traces")]
 static mut STACK_TRACES: StackTrace = StackTrace::with_max_entries(MAX_STACK_DEPTH, 0);
 
-// TODO(kakkoyun): Figure out map of map usage. Right now only unwinds the stack for a specific pid.
-// - or use composite keys.
-#[map(name = "pid")]
-static mut PID: Array<u32> = Array::with_max_entries(1, 0);
+// Build-id keyed counterpart of `STACK_TRACES`, selected with `build_id_unwinding`.
+#[map(name = "build_id_stack_traces")]
+static mut BUILD_ID_STACK_TRACES: StackTrace =
+    StackTrace::with_max_entries(MAX_STACK_DEPTH, BPF_F_STACK_BUILD_ID);
+
+// Per-pid base/len into the shared PC/RIP/RSP tables.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct UnwindTableMeta {
+    base: u32,
+    len: u32,
+}
+
+#[map(name = "unwind_table_meta")]
+static mut UNWIND_TABLE_META: HashMap<u32, UnwindTableMeta> =
+    HashMap::with_max_entries(MAX_UNWIND_TABLES, 0);
+
 #[map(name = "pc")]
-static mut PC: Array<u64> = Array::with_max_entries(EHFRAME_ENTRIES, 0);
+static mut PC: Array<u64> = Array::with_max_entries(EH_FRAME_ENTRIES, 0);
 #[map(name = "rip")]
-static mut RIP: Array<Instruction> = Array::with_max_entries(EHFRAME_ENTRIES, 0);
+static mut RIP: Array<Instruction> = Array::with_max_entries(EH_FRAME_ENTRIES, 0);
 #[map(name = "rsp")]
-static mut RSP: Array<Instruction> = Array::with_max_entries(EHFRAME_ENTRIES, 0);
+static mut RSP: Array<Instruction> = Array::with_max_entries(EH_FRAME_ENTRIES, 0);
 
+// Stack id -> frames, for symbolization; counting reuses COUNTS/StackCountKey below.
 #[map(name = "eh_frame_stack_traces")]
-static mut EH_FRAME_STACK_TRACES: HashMap<[u64; EH_FRAME_MAX_STACK_DEPTH], u32> =
-    HashMap::with_max_entries(1024, 0);
+static mut EH_FRAME_STACK_TRACES: HashMap<u32, [u64; MAX_STACK_DEPTH as usize]> =
+    HashMap::with_max_entries(EH_FRAME_STACK_TRACE_ENTRIES, 0);
+
+// Per-CPU scratch stack for the eh_frame unwinder; doesn't fit on the 512-byte eBPF stack.
+#[map(name = "eh_frame_stack")]
+static mut EH_FRAME_STACK: PerCpuArray<[u64; MAX_STACK_DEPTH as usize]> =
+    PerCpuArray::with_max_entries(1, 0);
 
 #[perf_event]
 fn profile_cpu(ctx: PerfEventContext) -> u32 {
@@ -80,18 +108,26 @@ unsafe fn try_profile_cpu(ctx: PerfEventContext) {
         return;
     }
 
-    // TODO(kakkoyun): Find the correct maps to use using PID and pass it along.
-    if let Some(pid) = PID.get(0) {
-        if *pid == ctx.pid() {
-            let mut stack = [0; EH_FRAME_MAX_STACK_DEPTH];
-            // TODO(kakkoyun): !!
-            // let regs = ctx.regs();
-            backtrace(regs, &mut stack);
-            let mut count = EH_FRAME_STACK_TRACES.get_mut(&stack).unwrap_or_default();
-            core::intrinsics::atomic_xadd_acqrel(count, 1);
-            EH_FRAME_STACK_TRACES.insert(&stack, &count, BPF_NOEXIST.into());
-            return;
+    if let Some(meta) = UNWIND_TABLE_META.get(&ctx.tgid()) {
+        if let Some(stack) = EH_FRAME_STACK.get_mut(0) {
+            let regs = ctx.regs();
+            backtrace(meta, regs, stack);
+            if let Some(stack_id) = intern_stack(stack) {
+                let mut key = StackCountKey {
+                    pid: ctx.tgid(),
+                    user_stack_id: stack_id as i32,
+                    kernel_stack_id: 0,
+                };
+                // Samples taken inside a syscall or kernel code still need their
+                // kernel frames, same as the fallback path below -- otherwise a
+                // precisely DWARF-unwound user stack loses everything above it.
+                if let Ok(stack_id) = STACK_TRACES.get_stackid(&ctx, 0) {
+                    key.kernel_stack_id = stack_id as i32;
+                }
+                try_update_count(&mut key);
+            }
         }
+        return;
     }
 
 
@@ -101,6 +137,23 @@ unsafe fn try_profile_cpu(ctx: PerfEventContext) {
         kernel_stack_id: 0,
     };
 
+    // build_id_unwinding: resolve against the build-id map; fall back to a
+    // raw address only if capturing the build-id stack itself fails (map
+    // full, hash collision). Individual frames with an unresolved build-id
+    // (anonymous/JIT mappings) still come back inside a successful capture
+    // -- there's no per-frame fallback here, since reading each frame's
+    // `BuildIdFrame.status` happens downstream, in the symbolizer reading
+    // this map, not in this program.
+    #[cfg(feature = "build_id_unwinding")]
+    {
+        let build_id_flags = BPF_F_USER_STACK.into();
+        if let Ok(stack_id) = BUILD_ID_STACK_TRACES.get_stackid(&ctx, build_id_flags) {
+            key.user_stack_id = stack_id as i32;
+        } else if let Ok(stack_id) = STACK_TRACES.get_stackid(&ctx, BPF_F_USER_STACK.into()) {
+            key.user_stack_id = stack_id as i32;
+        }
+    }
+    #[cfg(not(feature = "build_id_unwinding"))]
     if let Ok(stack_id) = STACK_TRACES.get_stackid(&ctx, BPF_F_USER_STACK.into()) {
         key.user_stack_id = stack_id as i32;
     }
@@ -126,41 +179,146 @@ unsafe fn try_update_count(key: &mut StackCountKey) {
     }
 }
 
-unsafe fn backtrace(regs: &PtRegs, stack: &mut [u64; EH_FRAME_MAX_STACK_DEPTH]) {
+unsafe fn backtrace(meta: &UnwindTableMeta, regs: &PtRegs, stack: &mut [u64; MAX_STACK_DEPTH as usize]) {
+    // EH_FRAME_STACK is a per-CPU slot reused across samples, not a fresh
+    // local, so clear it first -- otherwise the tail past where we stop
+    // unwinding still holds a previous sample's frames, and intern_stack
+    // would hash/compare that leftover noise as part of the key.
+    *stack = [0; MAX_STACK_DEPTH as usize];
     let mut rip = regs.rip;
     let mut rsp = regs.rsp;
+    let mut rbp = regs.rbp;
     for d in 0..MAX_STACK_DEPTH {
         stack[d] = rip;
         if rip == 0 {
             break;
         }
-        let i = binary_search(rip);
+        let i = binary_search(meta, rip);
 
+        // A missing RSP/RIP row or a CFA we can't compute means this PC has
+        // no eh_frame coverage -- common at leaf functions in hand-written
+        // assembly, JIT code, or libraries built without CFI. Fall back to a
+        // classic %rbp walk for this frame and resume DWARF unwinding on the
+        // next one if a covered PC reappears, rather than truncating here.
         let ins = if let Some(ins) = RSP.get_mut(i) {
             ins
+        } else if let Some((next_rip, next_rsp, next_rbp)) = frame_pointer_walk(rbp) {
+            rip = next_rip;
+            rsp = next_rsp;
+            rbp = next_rbp;
+            continue;
         } else {
             break;
         };
         let cfa = if let Some(cfa) = execute_instruction(&ins, rip, rsp, 0) {
             cfa
+        } else if let Some((next_rip, next_rsp, next_rbp)) = frame_pointer_walk(rbp) {
+            rip = next_rip;
+            rsp = next_rsp;
+            rbp = next_rbp;
+            continue;
         } else {
             break;
         };
 
         let ins = if let Some(ins) = RIP.get_mut(i) {
             ins
+        } else if let Some((next_rip, next_rsp, next_rbp)) = frame_pointer_walk(rbp) {
+            rip = next_rip;
+            rsp = next_rsp;
+            rbp = next_rbp;
+            continue;
         } else {
             break;
         };
-        rip = execute_instruction(&ins, rip, rsp, cfa).unwrap_or_default();
-        rsp = cfa;
+        rip = if let Some(next_rip) = execute_instruction(&ins, rip, rsp, cfa) {
+            rsp = cfa;
+            // The CFI VM has no rule to restore %rbp, so a run of DWARF-only
+            // frames would otherwise leave it stale by the time a later gap
+            // needs it. x86-64 frame-pointer prologues save the caller's
+            // %rbp at CFA-16; read it back so frame_pointer_walk always has
+            // an up-to-date base, not just the entry frame's.
+            if let Some(saved_rbp) = read_saved_rbp(cfa) {
+                rbp = saved_rbp;
+            }
+            next_rip
+        } else if let Some((next_rip, next_rsp, next_rbp)) = frame_pointer_walk(rbp) {
+            rsp = next_rsp;
+            rbp = next_rbp;
+            next_rip
+        } else {
+            break;
+        };
+    }
+}
+
+// Classic frame-pointer walk used when a PC falls outside eh_frame coverage:
+// `%rbp` points at the saved caller `%rbp` followed by the return address.
+unsafe fn frame_pointer_walk(rbp: u64) -> Option<(u64, u64, u64)> {
+    if rbp == 0 {
+        return None;
+    }
+    let mut next_rbp: u64 = 0;
+    let mut ret: u64 = 0;
+    if bpf_probe_read(&mut next_rbp as *mut _ as *mut _, 8, rbp as *const core::ffi::c_void) != 0 {
+        return None;
+    }
+    if bpf_probe_read(&mut ret as *mut _ as *mut _, 8, (rbp + 8) as *const core::ffi::c_void) != 0 {
+        return None;
+    }
+    Some((ret, rbp + 16, next_rbp))
+}
+
+unsafe fn read_saved_rbp(cfa: u64) -> Option<u64> {
+    let mut saved_rbp: u64 = 0;
+    let src = (cfa - 16) as *const core::ffi::c_void;
+    if bpf_probe_read(&mut saved_rbp as *mut _ as *mut _, 8, src) == 0 {
+        Some(saved_rbp)
+    } else {
+        None
+    }
+}
+
+// jhash-style mix over the stack's IP words.
+fn stack_hash(stack: &[u64; MAX_STACK_DEPTH as usize]) -> u32 {
+    let mut h: u32 = stack.len() as u32;
+    for ip in stack.iter() {
+        for w in [*ip as u32, (*ip >> 32) as u32] {
+            h = h.wrapping_add(w);
+            h = h.wrapping_mul(0x9e3779b9) ^ (h >> 16);
+        }
+    }
+    h
+}
+
+// Interns `stack` under a small hashed id, linearly probing past hash
+// collisions (comparing stored frames to detect a true collision vs. a
+// repeat lookup of the same stack) so `EH_FRAME_STACK_TRACES` stays keyed by
+// a 4-byte id instead of the full frame array. Returns `None` if every
+// probed slot is taken by a different stack, or if the final insert races
+// another CPU and loses -- the caller must not attribute a sample to an id
+// it didn't actually claim.
+unsafe fn intern_stack(stack: &[u64; MAX_STACK_DEPTH as usize]) -> Option<u32> {
+    let mut id = stack_hash(stack) % EH_FRAME_STACK_TRACE_ENTRIES;
+    for _ in 0..MAX_STACK_PROBE_DEPTH {
+        match EH_FRAME_STACK_TRACES.get(&id) {
+            Some(existing) if existing == stack => return Some(id),
+            Some(_) => id = (id + 1) % EH_FRAME_STACK_TRACE_ENTRIES,
+            None => {
+                return EH_FRAME_STACK_TRACES
+                    .insert(&id, stack, BPF_NOEXIST.into())
+                    .map(|_| id)
+                    .ok();
+            }
+        }
     }
+    None
 }
 
-unsafe fn binary_search(rip: u64) -> u32 {
-    let mut left = 0;
-    let mut right = CONFIG.get(0).unwrap_or(1) - 1;
-    let mut i = 0;
+unsafe fn binary_search(meta: &UnwindTableMeta, rip: u64) -> u32 {
+    let mut left = meta.base;
+    let mut right = meta.base + meta.len.saturating_sub(1);
+    let mut i = left;
     for _ in 0..MAX_BIN_SEARCH_DEPTH {
         if left > right {
             break;
@@ -181,7 +339,7 @@ fn execute_instruction(ins: &Instruction, rip: u64, rsp: u64, cfa: u64) -> Optio
         1 => {
             let unsafe_ptr = (cfa as i64 + ins.offset as i64) as *const core::ffi::c_void;
             let mut res: u64 = 0;
-            if unsafe { sys::bpf_probe_read(&mut res as *mut _ as *mut _, 8, unsafe_ptr) } == 0 {
+            if unsafe { bpf_probe_read(&mut res as *mut _ as *mut _, 8, unsafe_ptr) } == 0 {
                 Some(res)
             } else {
                 None